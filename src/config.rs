@@ -0,0 +1,58 @@
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The top-level shape of a sniproxy TOML config file: a list of independent listeners, each with
+/// its own bind address and backend settings.
+#[derive(Deserialize)]
+pub struct Config {
+    pub listen: Vec<ListenerConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+
+    #[serde(default = "default_backend_root")]
+    pub backend_root: PathBuf,
+
+    #[serde(default = "default_handshake_timeout_secs")]
+    pub handshake_timeout_secs: u64,
+
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+
+    #[serde(default)]
+    pub default_backend: Option<PathBuf>,
+}
+
+fn default_backend_root() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_handshake_timeout_secs() -> u64 {
+    10
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+impl ListenerConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+pub fn load(path: &Path) -> std::io::Result<Config> {
+    let text = std::fs::read_to_string(path)?;
+    toml::from_str(&text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
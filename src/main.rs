@@ -1,13 +1,19 @@
 // This implementation is inspired by https://github.com/dlundquist/sniproxy, but I wrote it from
 // scratch based on a careful reading of the TLS 1.3 specification.
 
+mod config;
+
+use config::ListenerConfig;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::time::Duration;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt, Error, ErrorKind};
 use tokio::net;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, Semaphore};
 use tokio::task;
-use tokio::time::{timeout, Elapsed};
+use tokio::time::{delay_for, timeout, Elapsed};
 
 // Unless otherwise specified, all quotes are from RFC 8446 (TLS 1.3).
 
@@ -21,6 +27,8 @@ const TLS_HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
 const TLS_EXTENSION_SNI: usize = 0x0000;
 const TLS_SNI_HOST_NAME_TYPE: u8 = 0;
 
+const TLS_EXTENSION_ALPN: usize = 0x0010;
+
 const TLS_ALERT_CONTENT_TYPE: u8 = 21;
 const TLS_ALERT_LENGTH: [u8; 2] = [0x00, 0x02];
 const TLS_ALERT_LEVEL_FATAL: u8 = 2;
@@ -136,7 +144,122 @@ impl<R: AsyncReadExt> TlsHandshakeReader<R> {
     }
 }
 
-async fn get_server_name<R: AsyncReadExt>(source: &mut TlsHandshakeReader<R>) -> TlsResult<String> {
+// Parses a server_name extension's extension_data (RFC 6066 section 3) and returns the first
+// host_name entry, if any. Fully consumes `length`.
+async fn parse_server_name<R: AsyncReadExt>(
+    source: &mut TlsHandshakeReader<R>,
+    length: &mut usize,
+) -> TlsResult<Option<String>> {
+    // This extension ends immediately after server_name_list
+    check_length(2, length)?;
+    if *length != source.read_length(2).await? {
+        return Err(TlsError::DecodeError);
+    }
+
+    while *length > 0 {
+        check_length(3, length)?;
+        let name_type = source.read().await?;
+        let name_length = source.read_length(2).await?;
+
+        if name_type != TLS_SNI_HOST_NAME_TYPE {
+            source.seek(name_length, length)?;
+            continue;
+        }
+
+        check_length(name_length, length)?;
+
+        // RFC 6066 section 3: "The ServerNameList MUST NOT contain more than one name of the
+        // same name_type." So we can just extract the first one we find.
+
+        // Hostnames are limited to 255 octets with a trailing dot, but RFC 6066 prohibits the
+        // trailing dot, so the limit here is 254 octets. Enforcing this limit ensures an
+        // attacker can't make us heap-allocate 64kB for a hostname we'll never match.
+        if name_length > 254 {
+            return Err(TlsError::UnrecognizedName);
+        }
+
+        // The following validation rules ensure that we won't return a hostname which could
+        // lead to pathname traversal (e.g. "..", "", or "a/b") and that semantically
+        // equivalent hostnames are only returned in a canonical form. This does not validate
+        // anything else about the hostname, such as length limits on individual labels.
+
+        let mut name = Vec::with_capacity(name_length);
+        let mut start_of_label = true;
+        for _ in 0..name_length {
+            let b = source.read().await?.to_ascii_lowercase();
+
+            if start_of_label && (b == b'-' || b == b'.') {
+                // a hostname label can't start with dot or dash
+                return Err(TlsError::UnrecognizedName);
+            }
+
+            // the next byte is the start of a label iff this one was a dot
+            start_of_label = b'.' == b;
+
+            match b {
+                b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' => name.push(b),
+                _ => return Err(TlsError::UnrecognizedName),
+            }
+        }
+
+        // If we're expecting a new label after reading the whole hostname, then either the
+        // name was empty or it ended with a dot; neither is allowed.
+        if start_of_label {
+            return Err(TlsError::UnrecognizedName);
+        }
+
+        // There's nothing else we care about in server_name_list, so skip past any further
+        // entries instead of parsing them.
+        source.seek(*length, length)?;
+
+        // safety: every byte was already checked for being a valid subset of UTF-8
+        let name = unsafe { String::from_utf8_unchecked(name) };
+        return Ok(Some(name));
+    }
+
+    // None of the names were of the right type, and section 4.2 says "There MUST NOT be more
+    // than one extension of the same type in a given extension block", so there definitely
+    // isn't a server name in this ClientHello.
+    Ok(None)
+}
+
+// Parses an ALPN extension's extension_data (RFC 7301 section 3.1) and returns the client's
+// offered protocol name list, in the order the client sent them. Fully consumes `length`.
+async fn parse_alpn<R: AsyncReadExt>(
+    source: &mut TlsHandshakeReader<R>,
+    length: &mut usize,
+) -> TlsResult<Vec<String>> {
+    // This extension ends immediately after ProtocolNameList
+    check_length(2, length)?;
+    if *length != source.read_length(2).await? {
+        return Err(TlsError::DecodeError);
+    }
+
+    let mut protocols = Vec::new();
+    while *length > 0 {
+        check_length(1, length)?;
+        let protocol_length = source.read_length(1).await?;
+        check_length(protocol_length, length)?;
+
+        let mut protocol = Vec::with_capacity(protocol_length);
+        for _ in 0..protocol_length {
+            protocol.push(source.read().await?);
+        }
+
+        // RFC 7301 doesn't actually require protocol names to be ASCII, but every registered
+        // ALPN identifier is, and we only ever compare these against our own ASCII socket names.
+        match String::from_utf8(protocol) {
+            Ok(protocol) => protocols.push(protocol),
+            Err(_) => return Err(TlsError::DecodeError),
+        }
+    }
+
+    Ok(protocols)
+}
+
+async fn get_server_name<R: AsyncReadExt>(
+    source: &mut TlsHandshakeReader<R>,
+) -> TlsResult<(String, Vec<String>)> {
     // section 4.1.2: "When a client first connects to a server, it is REQUIRED to send the
     // ClientHello as its first TLS message."
     if source.read().await? != TLS_HANDSHAKE_TYPE_CLIENT_HELLO {
@@ -185,128 +308,377 @@ async fn get_server_name<R: AsyncReadExt>(source: &mut TlsHandshakeReader<R>) ->
         return Err(TlsError::DecodeError);
     }
 
+    // section 4.2: "There MUST NOT be more than one extension of the same type in a given
+    // extension block", so we only need to look at the first SNI and first ALPN extension we
+    // find; keep scanning past that point in case the other one comes later.
+    let mut name = None;
+    let mut alpn = Vec::new();
+
     while hello_length > 0 {
         check_length(4, &mut hello_length)?;
         let extension = source.read_length(2).await?;
         let mut length = source.read_length(2).await?;
 
-        if extension != TLS_EXTENSION_SNI {
-            source.seek(length, &mut hello_length)?;
-            continue;
+        match extension {
+            TLS_EXTENSION_SNI if name.is_none() => {
+                check_length(length, &mut hello_length)?;
+                name = parse_server_name(source, &mut length).await?;
+            }
+            TLS_EXTENSION_ALPN if alpn.is_empty() => {
+                check_length(length, &mut hello_length)?;
+                alpn = parse_alpn(source, &mut length).await?;
+            }
+            _ => source.seek(length, &mut hello_length)?,
         }
+    }
 
-        check_length(length, &mut hello_length)?;
+    // Like when the extensions block is absent, pretend as if a server name was present but not
+    // recognized.
+    match name {
+        Some(name) => Ok((name, alpn)),
+        None => Err(TlsError::UnrecognizedName),
+    }
+}
 
-        // This extension ends immediately after server_name_list
-        check_length(2, &mut length)?;
-        if length != source.read_length(2).await? {
-            return Err(TlsError::DecodeError);
+// PROXY protocol version 2 (see https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt,
+// section 2.5): a fixed 12-byte signature followed by the version/command byte.
+const PROXY_V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+const PROXY_V2_VERSION_COMMAND: u8 = 0x21; // version 2, command PROXY
+const PROXY_V2_FAMILY_INET: u8 = 0x11; // AF_INET + STREAM
+const PROXY_V2_FAMILY_INET6: u8 = 0x21; // AF_INET6 + STREAM
+const PROXY_V2_FAMILY_UNSPEC: u8 = 0x00; // AF_UNSPEC, no addresses carried
+
+// section 2.2: encode `remote`/`local` as the PROXY v2 address block, with its header.
+fn proxy_v2_header(remote: SocketAddr, local: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(28);
+    header.extend_from_slice(&PROXY_V2_SIGNATURE);
+    header.push(PROXY_V2_VERSION_COMMAND);
+
+    match (remote, local) {
+        (SocketAddr::V4(remote), SocketAddr::V4(local)) => {
+            header.push(PROXY_V2_FAMILY_INET);
+            header.extend_from_slice(&(12u16).to_be_bytes());
+            header.extend_from_slice(&remote.ip().octets());
+            header.extend_from_slice(&local.ip().octets());
+            header.extend_from_slice(&remote.port().to_be_bytes());
+            header.extend_from_slice(&local.port().to_be_bytes());
         }
+        (remote, local) => {
+            header.push(PROXY_V2_FAMILY_INET6);
+            header.extend_from_slice(&(36u16).to_be_bytes());
+
+            // section 2.2: "the address block is always padded to respect the address length of
+            // the given family" and mixed-family pairs can't actually happen since both ends of a
+            // TCP connection share an address family, but handle it by widening to IPv6 so this
+            // can't panic if that ever changes.
+            let to_v6 = |addr: SocketAddr| match addr.ip() {
+                std::net::IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                std::net::IpAddr::V6(ip) => ip,
+            };
+            header.extend_from_slice(&to_v6(remote).octets());
+            header.extend_from_slice(&to_v6(local).octets());
+            header.extend_from_slice(&remote.port().to_be_bytes());
+            header.extend_from_slice(&local.port().to_be_bytes());
+        }
+    }
 
-        while length > 0 {
-            check_length(3, &mut length)?;
-            let name_type = source.read().await?;
-            let name_length = source.read_length(2).await?;
+    header
+}
 
-            if name_type != TLS_SNI_HOST_NAME_TYPE {
-                source.seek(name_length, &mut length)?;
-                continue;
-            }
+// Parse a PROXY protocol v1 or v2 header from the front of `source`, returning the
+// (source, destination) addresses it declares, or None if the header doesn't carry addresses
+// (the v1 UNKNOWN command or the v2 LOCAL command, used for e.g. HAProxy health checks) -- in
+// which case the caller should keep using the addresses accept() gave it. Used when sniproxy
+// sits behind another L4 proxy that doesn't preserve the original client address itself.
+async fn read_proxy_header<R: AsyncReadExt>(
+    source: &mut R,
+) -> TlsResult<Option<(SocketAddr, SocketAddr)>> {
+    let mut prefix = [0u8; PROXY_V2_SIGNATURE.len()];
+    source.read_exact(&mut prefix).await?;
+
+    if prefix == PROXY_V2_SIGNATURE {
+        read_proxy_v2_header(source).await
+    } else {
+        read_proxy_v1_header(source, prefix).await
+    }
+}
 
-            check_length(name_length, &mut length)?;
+async fn read_proxy_v2_header<R: AsyncReadExt>(
+    source: &mut R,
+) -> TlsResult<Option<(SocketAddr, SocketAddr)>> {
+    let mut header = [0u8; 4];
+    source.read_exact(&mut header).await?;
 
-            // RFC 6066 section 3: "The ServerNameList MUST NOT contain more than one name of the
-            // same name_type." So we can just extract the first one we find.
+    // High nibble is the version, which must be 2; low nibble is the command, 0 (LOCAL) or 1
+    // (PROXY). Anything else is a version we don't understand.
+    let command = header[0] & 0x0f;
+    if header[0] & 0xf0 != 0x20 || command > 1 {
+        return Err(TlsError::DecodeError);
+    }
 
-            // Hostnames are limited to 255 octets with a trailing dot, but RFC 6066 prohibits the
-            // trailing dot, so the limit here is 254 octets. Enforcing this limit ensures an
-            // attacker can't make us heap-allocate 64kB for a hostname we'll never match.
-            if name_length > 254 {
-                return Err(TlsError::UnrecognizedName);
-            }
+    let family = header[1];
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
 
-            // The following validation rules ensure that we won't return a hostname which could
-            // lead to pathname traversal (e.g. "..", "", or "a/b") and that semantically
-            // equivalent hostnames are only returned in a canonical form. This does not validate
-            // anything else about the hostname, such as length limits on individual labels.
+    let mut address_block = vec![0u8; length];
+    source.read_exact(&mut address_block).await?;
 
-            let mut name = Vec::with_capacity(name_length);
-            let mut start_of_label = true;
-            for _ in 0..name_length {
-                let b = source.read().await?.to_ascii_lowercase();
+    // section 2.1: LOCAL connections (e.g. HAProxy's own health checks) and AF_UNSPEC carry no
+    // usable addresses; "the receiver must accept the connection and use the real connection
+    // endpoints", i.e. whatever accept() gave us.
+    if command == 0 || family == PROXY_V2_FAMILY_UNSPEC {
+        return Ok(None);
+    }
 
-                if start_of_label && (b == b'-' || b == b'.') {
-                    // a hostname label can't start with dot or dash
-                    return Err(TlsError::UnrecognizedName);
-                }
+    match family {
+        PROXY_V2_FAMILY_INET if length >= 12 => {
+            let src = SocketAddr::new(
+                std::net::Ipv4Addr::new(
+                    address_block[0],
+                    address_block[1],
+                    address_block[2],
+                    address_block[3],
+                )
+                .into(),
+                u16::from_be_bytes([address_block[8], address_block[9]]),
+            );
+            let dst = SocketAddr::new(
+                std::net::Ipv4Addr::new(
+                    address_block[4],
+                    address_block[5],
+                    address_block[6],
+                    address_block[7],
+                )
+                .into(),
+                u16::from_be_bytes([address_block[10], address_block[11]]),
+            );
+            Ok(Some((src, dst)))
+        }
+        PROXY_V2_FAMILY_INET6 if length >= 36 => {
+            let mut src_octets = [0u8; 16];
+            src_octets.copy_from_slice(&address_block[0..16]);
+            let mut dst_octets = [0u8; 16];
+            dst_octets.copy_from_slice(&address_block[16..32]);
+
+            let src = SocketAddr::new(
+                std::net::Ipv6Addr::from(src_octets).into(),
+                u16::from_be_bytes([address_block[32], address_block[33]]),
+            );
+            let dst = SocketAddr::new(
+                std::net::Ipv6Addr::from(dst_octets).into(),
+                u16::from_be_bytes([address_block[34], address_block[35]]),
+            );
+            Ok(Some((src, dst)))
+        }
+        _ => Err(TlsError::DecodeError),
+    }
+}
 
-                // the next byte is the start of a label iff this one was a dot
-                start_of_label = b'.' == b;
+async fn read_proxy_v1_header<R: AsyncReadExt>(
+    source: &mut R,
+    prefix: [u8; 12],
+) -> TlsResult<Option<(SocketAddr, SocketAddr)>> {
+    // The spec caps a v1 header at 107 bytes including the trailing "\r\n".
+    const PROXY_V1_MAX_LENGTH: usize = 107;
 
-                match b {
-                    b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' => name.push(b),
-                    _ => return Err(TlsError::UnrecognizedName),
-                }
-            }
+    let mut line = Vec::with_capacity(PROXY_V1_MAX_LENGTH);
+    line.extend_from_slice(&prefix);
 
-            // If we're expecting a new label after reading the whole hostname, then either the
-            // name was empty or it ended with a dot; neither is allowed.
-            if start_of_label {
-                return Err(TlsError::UnrecognizedName);
-            }
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_V1_MAX_LENGTH {
+            return Err(TlsError::DecodeError);
+        }
 
-            // safety: every byte was already checked for being a valid subset of UTF-8
-            let name = unsafe { String::from_utf8_unchecked(name) };
-            return Ok(name);
+        let mut byte = [0u8; 1];
+        source.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| TlsError::DecodeError)?;
+    let mut fields = line.split(' ');
+
+    if fields.next() != Some("PROXY") {
+        return Err(TlsError::DecodeError);
+    }
+
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        // UNKNOWN carries no usable addresses (e.g. a balancer's own health checks); per spec,
+        // "the receiver ... must ignore anything presented before the CRLF" and use the
+        // addresses accept() gave it, same as the v2 LOCAL/AF_UNSPEC case above.
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(TlsError::DecodeError),
+    }
+
+    let parse_addr = |s: Option<&str>| -> TlsResult<std::net::IpAddr> {
+        s.ok_or(TlsError::DecodeError)?
+            .parse()
+            .map_err(|_| TlsError::DecodeError)
+    };
+    let parse_port = |s: Option<&str>| -> TlsResult<u16> {
+        s.ok_or(TlsError::DecodeError)?
+            .parse()
+            .map_err(|_| TlsError::DecodeError)
+    };
+
+    let src_ip = parse_addr(fields.next())?;
+    let dst_ip = parse_addr(fields.next())?;
+    let src_port = parse_port(fields.next())?;
+    let dst_port = parse_port(fields.next())?;
+
+    if fields.next().is_some() {
+        return Err(TlsError::DecodeError);
+    }
+
+    Ok(Some((
+        SocketAddr::new(src_ip, src_port),
+        SocketAddr::new(dst_ip, dst_port),
+    )))
+}
+
+// Maps a negotiated ALPN protocol identifier to the backend socket name we'd look for it under,
+// if we know of one. Protocols we don't recognize just fall back to "tls-socket" like before.
+fn alpn_socket_name(protocol: &str) -> Option<&'static str> {
+    match protocol {
+        "h2" => Some("h2-socket"),
+        "http/1.1" => Some("http1.1-socket"),
+        "http/1.0" => Some("http1.0-socket"),
+        _ => None,
+    }
+}
+
+// The settings a single configured listener applies to every connection it accepts. When we're
+// not given a config file, main_loop() builds one of these from environment variables instead so
+// the fd-0 socket-activation path behaves the same as always.
+struct ListenerSettings {
+    backend_root: PathBuf,
+    handshake_timeout: Duration,
+    accept_proxy_protocol: bool,
+    idle_timeout: Duration,
+    default_backend: Option<PathBuf>,
+}
+
+impl From<&ListenerConfig> for ListenerSettings {
+    fn from(config: &ListenerConfig) -> Self {
+        ListenerSettings {
+            backend_root: config.backend_root.clone(),
+            handshake_timeout: config.handshake_timeout(),
+            accept_proxy_protocol: config.accept_proxy_protocol,
+            idle_timeout: config.idle_timeout(),
+            default_backend: config.default_backend.clone(),
         }
+    }
+}
 
-        // None of the names were of the right type, and section 4.2 says "There MUST NOT be more
-        // than one extension of the same type in a given extension block", so there definitely
-        // isn't a server name in this ClientHello.
-        break;
+// Try each of `socket_names` in turn under `path`. Consider it a valid backend if connecting to
+// one of them doesn't return any of these errors:
+// - is a directory (NotFound after joining a relative path)
+// - which contains an entry with that name (NotFound)
+// - which is accessible to this proxy (PermissionDenied)
+// - and is a listening socket (ConnectionRefused)
+// If it isn't, then that's the error to report. Anything else is not the client's fault. A
+// NotFound on anything but the last candidate just means that backend doesn't support this
+// protocol, so move on to the next one instead of giving up.
+async fn connect_backend_socket(
+    path: &std::path::Path,
+    socket_names: &[&str],
+) -> TlsResult<net::UnixStream> {
+    let mut backend = None;
+    for (i, socket_name) in socket_names.iter().enumerate() {
+        let is_last = i + 1 == socket_names.len();
+        match net::UnixStream::connect(path.join(socket_name)).await {
+            Ok(stream) => {
+                backend = Some(stream);
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && !is_last => continue,
+            Err(e) => {
+                return Err(match e.kind() {
+                    ErrorKind::NotFound
+                    | ErrorKind::PermissionDenied
+                    | ErrorKind::ConnectionRefused => TlsError::UnrecognizedName,
+                    _ => TlsError::InternalError,
+                })
+            }
+        }
     }
 
-    // Like when the extensions block is absent, pretend as if a server name was present but not
-    // recognized.
-    Err(TlsError::UnrecognizedName)
+    // socket_names always has at least "tls-socket", and the loop above returns before falling
+    // through unless it found a working backend.
+    Ok(backend.expect("socket_names is non-empty"))
 }
 
 async fn connect_backend<R: AsyncReadExt>(
-    source: R,
+    mut source: R,
     local: SocketAddr,
     remote: SocketAddr,
+    settings: &ListenerSettings,
 ) -> TlsResult<(R, net::UnixStream)> {
+    // If we're behind another L4 proxy, trust its PROXY header instead of the addresses we got
+    // from accept(), which would otherwise just describe that proxy. Bound this read by the same
+    // handshake timeout as the TLS read below, so a peer that opens the connection but never
+    // finishes (or never sends) a PROXY header can't hold the task and its fd open forever.
+    let (local, remote) = if settings.accept_proxy_protocol {
+        match timeout(settings.handshake_timeout, read_proxy_header(&mut source)).await?? {
+            Some((remote, local)) => (local, remote),
+            None => (local, remote),
+        }
+    } else {
+        (local, remote)
+    };
+
     let mut source = TlsHandshakeReader::new(source);
 
-    // timeout can return a "Elapsed" error, or else return the result from get_server_name, which
-    // might be a TlsError. So there are two "?" here to unwrap both.
-    let name = timeout(Duration::from_secs(10), get_server_name(&mut source)).await??;
-
-    let path: &std::path::Path = name.as_ref();
-
-    // The client sent a name and it's been validated to be safe to use as a path. Consider it a
-    // valid server name if connecting to the path doesn't return any of these errors:
-    // - is a directory (NotFound after joining a relative path)
-    // - which contains an entry named "tls-socket" (NotFound)
-    // - which is accessible to this proxy (PermissionDenied)
-    // - and is a listening socket (ConnectionRefused)
-    // If it isn't a valid server name, then that's the error to report. Anything else is not the
-    // client's fault.
-    let mut backend = net::UnixStream::connect(path.join("tls-socket"))
-        .await
-        .map_err(|e| match e.kind() {
-            ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::ConnectionRefused => {
-                TlsError::UnrecognizedName
-            }
-            _ => TlsError::InternalError,
-        })?;
+    // timeout can return a "Elapsed" error, which we propagate with "?" same as always. The
+    // result from get_server_name, which might be a TlsError, is handled below instead of
+    // propagated immediately, so that an UnrecognizedName can fall back to
+    // settings.default_backend rather than aborting the handshake.
+    let server_name = timeout(settings.handshake_timeout, get_server_name(&mut source)).await?;
+
+    let (path, lookup_result) = match server_name {
+        Ok((name, alpn)) => {
+            let path = settings.backend_root.join(&name);
+
+            // Prefer a backend socket dedicated to the protocol the client negotiated via ALPN,
+            // if we have one, so operators can terminate e.g. HTTP/2 and HTTP/1.1 on different
+            // backends for the same hostname. "tls-socket" is always the last resort.
+            let mut socket_names: Vec<&str> =
+                alpn.iter().filter_map(|p| alpn_socket_name(p)).collect();
+            socket_names.push("tls-socket");
+
+            let result = connect_backend_socket(&path, &socket_names).await;
+            (path, result)
+        }
+        Err(e) => (settings.backend_root.clone(), Err(e)),
+    };
+
+    // If the client didn't give us a name we recognize -- no SNI at all, or a hostname with no
+    // matching backend directory -- fall back to settings.default_backend instead of aborting the
+    // handshake, if the operator configured one. Only do this for UnrecognizedName, which is the
+    // client's fault; an internal error talking to the real backend should still fail as before.
+    let (path, mut backend) = match lookup_result {
+        Ok(stream) => (path, stream),
+        Err(TlsError::UnrecognizedName) => match &settings.default_backend {
+            Some(default_backend) => (
+                default_backend.clone(),
+                connect_backend_socket(default_backend, &["tls-socket"]).await?,
+            ),
+            None => return Err(TlsError::UnrecognizedName),
+        },
+        Err(e) => return Err(e),
+    };
+    let path: &std::path::Path = &path;
 
     // After this point, all I/O errors are internal errors.
 
-    // If this file exists, turn on the PROXY protocol.
+    // If one of these files exists, turn on the PROXY protocol. Prefer v2 if both markers are
+    // present, since it's cheaper for the backend to parse.
     // NOTE: This is a blocking syscall, but stat should be fast enough that it's not worth
     // spawning off a thread.
-    if std::fs::metadata(path.join("send-proxy-v1")).is_ok() {
+    if std::fs::metadata(path.join("send-proxy-v2")).is_ok() {
+        backend.write_all(&proxy_v2_header(remote, local)).await?;
+    } else if std::fs::metadata(path.join("send-proxy-v1")).is_ok() {
         let header = format!(
             "PROXY {} {} {} {} {}\r\n",
             match remote {
@@ -326,10 +698,16 @@ async fn connect_backend<R: AsyncReadExt>(
     Ok((source, backend))
 }
 
-async fn handle_connection(mut client: net::TcpStream, local: SocketAddr, remote: SocketAddr) {
+async fn handle_connection(
+    mut client: net::TcpStream,
+    local: SocketAddr,
+    remote: SocketAddr,
+    settings: Rc<ListenerSettings>,
+) {
     let (client_in, mut client_out) = client.split();
 
-    let (client_in, mut backend) = match connect_backend(client_in, local, remote).await {
+    let (client_in, mut backend) = match connect_backend(client_in, local, remote, &settings).await
+    {
         Ok(r) => r,
         Err(e) => {
             // Try to send an alert before closing the connection, but if that fails, don't worry
@@ -353,48 +731,254 @@ async fn handle_connection(mut client: net::TcpStream, local: SocketAddr, remote
     let (backend_in, backend_out) = backend.split();
 
     // Ignore errors in either direction; just half-close the destination when the source stops
-    // being readable. And if that fails, ignore that too.
-    async fn copy_all<R, W>(mut from: R, mut to: W)
+    // being readable, or when no bytes have flowed in `idle_timeout`. A slow or wedged backend
+    // shouldn't be able to tie up a connection (and its fd) forever. If shutting down fails,
+    // ignore that too.
+    async fn copy_all<R, W>(mut from: R, mut to: W, idle_timeout: Duration)
     where
         R: AsyncReadExt + Unpin,
         W: AsyncWriteExt + Unpin,
     {
-        let _ = io::copy(&mut from, &mut to).await;
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = match timeout(idle_timeout, from.read(&mut buffer)).await {
+                Ok(Ok(n)) if n > 0 => n,
+                _ => break,
+            };
+
+            match timeout(idle_timeout, to.write_all(&buffer[..read])).await {
+                Ok(Ok(())) => {}
+                _ => break,
+            }
+        }
+
         let _ = to.shutdown().await;
     }
 
     tokio::join!(
-        copy_all(client_in, backend_out),
-        copy_all(backend_in, client_out),
+        copy_all(client_in, backend_out, settings.idle_timeout),
+        copy_all(backend_in, client_out, settings.idle_timeout),
     );
 }
 
-async fn main_loop() -> io::Result<()> {
-    // safety: the rest of the program must not use stdin
-    let listener = unsafe { std::os::unix::io::FromRawFd::from_raw_fd(0) };
-
-    // Assume stdin is an already bound and listening TCP socket.
-    let mut listener = net::TcpListener::from_std(listener)?;
+// Reads an environment variable and parses it, falling back to `default` if it's unset or
+// doesn't parse.
+fn env_var_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
 
-    // Asking for the listening socket's local address has the side effect of checking that it is
-    // actually a TCP socket.
-    let local = listener.local_addr()?;
+// A listener that's been bound and is being serviced by its own task, plus what we need to stop
+// that task again: sending on `stop` (or just dropping it) makes the accept loop return, and
+// `join` lets us wait for it to actually finish before, e.g., rebinding the same address.
+struct ListenerHandle {
+    stop: oneshot::Sender<()>,
+    join: task::JoinHandle<()>,
+}
 
-    println!("listening on {}", local);
+impl ListenerHandle {
+    async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.join.await;
+    }
+}
 
-    let mut graceful_shutdown = signal(SignalKind::hangup())?;
+// EMFILE ("too many open files" for this process) and ENFILE (same, system-wide) are exactly the
+// transient conditions a connection pile-up is expected to cause, and backing off briefly gives
+// some other connection a chance to close and free a descriptor. Anything else is unexpected, and
+// backing off wouldn't help, so don't keep spinning and logging on every accept() forever.
+fn is_transient_accept_error(e: &Error) -> bool {
+    const EMFILE: i32 = 24;
+    const ENFILE: i32 = 23;
+    matches!(e.raw_os_error(), Some(EMFILE) | Some(ENFILE))
+}
 
+// Accepts connections from `listener` until `stop` fires, handing each one off to its own task
+// (gated by `available_connections`) built from `settings`.
+async fn run_listener(
+    mut listener: net::TcpListener,
+    fallback_local_addr: SocketAddr,
+    settings: Rc<ListenerSettings>,
+    available_connections: Rc<Semaphore>,
+    mut stop: oneshot::Receiver<()>,
+) {
     loop {
-        tokio::select!(
-            result = listener.accept() => result.map(|(socket, remote)| {
-                let local = socket.local_addr().unwrap_or(local);
-                task::spawn_local(handle_connection(socket, local, remote));
-            })?,
-            Some(_) = graceful_shutdown.recv() => break,
+        // Acquire a permit before accepting at all, so a pile-up of connections we can't service
+        // yet throttles accept() itself instead of piling up accepted sockets (and their fds)
+        // behind the semaphore. The permit is forgotten below and released manually once the
+        // connection it's for is done, since it has to outlive this loop iteration.
+        let permit = tokio::select!(
+            permit = available_connections.acquire() => permit,
+            _ = &mut stop => break,
         );
+
+        let result = tokio::select!(
+            result = listener.accept() => result,
+            _ = &mut stop => break,
+        );
+
+        match result {
+            Ok((socket, remote)) => {
+                permit.forget();
+                let local = socket.local_addr().unwrap_or(fallback_local_addr);
+                let settings = settings.clone();
+                let available_connections = available_connections.clone();
+                task::spawn_local(async move {
+                    handle_connection(socket, local, remote, settings).await;
+                    available_connections.add_permits(1);
+                });
+            }
+            Err(e) if is_transient_accept_error(&e) => {
+                eprintln!("accept() failed, backing off: {}", e);
+                delay_for(Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                eprintln!("accept() failed, giving up on this listener: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn spawn_listener(
+    listener: net::TcpListener,
+    fallback_local_addr: SocketAddr,
+    settings: ListenerSettings,
+    available_connections: Rc<Semaphore>,
+) -> ListenerHandle {
+    println!("listening on {}", fallback_local_addr);
+
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let join = task::spawn_local(run_listener(
+        listener,
+        fallback_local_addr,
+        Rc::new(settings),
+        available_connections,
+        stop_rx,
+    ));
+
+    ListenerHandle {
+        stop: stop_tx,
+        join,
+    }
+}
+
+// Binds every listener described by `config`, stopping any of them that were already started if
+// a later one fails to bind, so we don't leak a partially-started listener set on a reload that
+// turns out to be broken.
+async fn spawn_configured_listeners(
+    config: config::Config,
+    available_connections: &Rc<Semaphore>,
+) -> io::Result<Vec<ListenerHandle>> {
+    let mut handles = Vec::with_capacity(config.listen.len());
+    for listener_config in &config.listen {
+        match net::TcpListener::bind(listener_config.address).await {
+            Ok(listener) => handles.push(spawn_listener(
+                listener,
+                listener_config.address,
+                ListenerSettings::from(listener_config),
+                available_connections.clone(),
+            )),
+            Err(e) => {
+                for handle in handles {
+                    handle.stop().await;
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(handles)
+}
+
+async fn main_loop() -> io::Result<()> {
+    // An optional path to a TOML config file, given as the program's first argument. Without one,
+    // we fall back to the fd-0 socket-activation behavior this proxy has always had, so existing
+    // deployments aren't affected.
+    let config_path = std::env::args_os().nth(1).map(PathBuf::from);
+
+    // Bound how many connections we'll service at once, so a pile-up of slow or stuck backends
+    // can't exhaust our file descriptors. This limit is shared across every listener, and across
+    // config reloads.
+    let max_connections = env_var_or("SNIPROXY_MAX_CONNECTIONS", 10_000usize);
+    let available_connections = Rc::new(Semaphore::new(max_connections));
+
+    let mut graceful_shutdown = signal(SignalKind::hangup())?;
+
+    match config_path {
+        None => {
+            // safety: the rest of the program must not use stdin
+            let listener = unsafe { std::os::unix::io::FromRawFd::from_raw_fd(0) };
+
+            // Assume stdin is an already bound and listening TCP socket.
+            let listener = net::TcpListener::from_std(listener)?;
+
+            // Asking for the listening socket's local address has the side effect of checking
+            // that it is actually a TCP socket.
+            let local = listener.local_addr()?;
+
+            let settings = ListenerSettings {
+                backend_root: PathBuf::new(),
+                handshake_timeout: Duration::from_secs(10),
+                accept_proxy_protocol: std::env::var_os("SNIPROXY_ACCEPT_PROXY_PROTOCOL").is_some(),
+                idle_timeout: Duration::from_secs(env_var_or("SNIPROXY_IDLE_TIMEOUT_SECS", 300u64)),
+                default_backend: std::env::var_os("SNIPROXY_DEFAULT_BACKEND").map(PathBuf::from),
+            };
+
+            let handle = spawn_listener(listener, local, settings, available_connections);
+
+            graceful_shutdown.recv().await;
+            println!("got SIGHUP, shutting down");
+            handle.stop().await;
+        }
+        Some(config_path) => {
+            let config = config::load(&config_path)?;
+            let mut listeners = spawn_configured_listeners(config, &available_connections).await?;
+
+            while graceful_shutdown.recv().await.is_some() {
+                println!("got SIGHUP, reloading {}", config_path.display());
+
+                // Parse the new config before touching the listeners that are already running,
+                // so a typo in the config file doesn't tear down a working proxy -- we just keep
+                // serving the old config until it's fixed. We still have to stop the old
+                // listeners before binding the new ones, since a listener that's still running
+                // holds its address.
+                let config = match config::load(&config_path) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        eprintln!(
+                            "failed to reload {}, keeping existing listeners: {}",
+                            config_path.display(),
+                            e
+                        );
+                        continue;
+                    }
+                };
+
+                for handle in listeners.drain(..) {
+                    handle.stop().await;
+                }
+
+                match spawn_configured_listeners(config, &available_connections).await {
+                    Ok(new_listeners) => listeners = new_listeners,
+                    Err(e) => {
+                        eprintln!(
+                            "failed to reload {}, no listeners are active: {}",
+                            config_path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+
+            for handle in listeners {
+                handle.stop().await;
+            }
+        }
     }
 
-    println!("got SIGHUP, shutting down");
     Ok(())
 }
 